@@ -4,14 +4,22 @@ use std::{
         Read,
         Write,
     },
+    net,
     os::unix::{
-        io::AsRawFd,
+        io::{
+            AsRawFd,
+            RawFd,
+        },
         net::UnixStream,
     },
     path::Path,
-    sync::atomic::{
-        AtomicBool,
-        Ordering,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        mpsc,
+        Arc,
     },
     thread,
 };
@@ -22,9 +30,17 @@ use anyhow::{
 };
 use byteorder::{
     LittleEndian,
-    ReadBytesExt,
     WriteBytesExt,
 };
+use mio::{
+    unix::SourceFd,
+    Events,
+    Interest,
+    Poll,
+    Registry,
+    Token,
+    Waker,
+};
 use serde_derive::{
     Deserialize,
     Serialize,
@@ -117,9 +133,16 @@ pub enum SessionMessageRequestPayload {
 }
 
 /// ResizeRequest resizes the pty for a given named session.
-/// We use an out-of-band request rather than doing this
-/// in the input stream because we don't want to have to
-/// introduce a framing protocol for the input stream.
+///
+/// This used to be the only way to resize a session, routed entirely
+/// out-of-band through a `SessionMessageRequest`, because doing it
+/// in-band would have meant introducing a framing protocol for the
+/// input stream. Now that `ChunkKind::Control` frames provide that
+/// framing, `shpool attach` prefers to send resizes inline (see
+/// `ControlMessage::Resize`) so they apply in order with the keystrokes
+/// that triggered them. This RPC form is kept around for session
+/// messages that need to reach a session without a live attach loop to
+/// carry a control frame.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ResizeRequest {
     /// The size of the client's tty
@@ -167,6 +190,35 @@ pub struct AttachHeader {
     /// shpool forks off a process. For now the list is just `SSH_AUTH_SOCK`
     /// and `TERM`.
     pub local_env: Vec<(String, String)>,
+    /// The number of bytes of `ChunkKind::Data` the daemon may have
+    /// in flight to this client before it must pause the pty reader and
+    /// wait for a `ChunkKind::Credit` frame topping the window back up.
+    /// A value of 0 means "use the daemon's default window", since a
+    /// real zero-byte window would wedge the session immediately.
+    pub initial_window: u32,
+    /// How many bytes of scrollback to replay from the session's ring
+    /// buffer before resuming live output, when reattaching to a session
+    /// that was already running (`AttachStatus::Attached`). A value of 0
+    /// means "don't replay anything"; `usize::MAX` asks for as much as the
+    /// daemon happens to have buffered. See `Client::pipe_bytes`'s
+    /// `scrollback_bytes` parameter for how the client side of the replay
+    /// is actually driven.
+    pub replay_bytes: usize,
+    /// The output compression algorithms this client is willing to accept,
+    /// in preference order. The daemon picks the first one it also
+    /// supports and echoes its choice back in
+    /// `AttachReplyHeader::compression`; an empty list means the client
+    /// only accepts uncompressed output.
+    pub compression: Vec<CompressionAlgo>,
+    /// How long the daemon should wait between `ChunkKind::Heartbeat`
+    /// frames, in milliseconds. 0 means "use the daemon's default".
+    pub heartbeat_interval_ms: u64,
+    /// How long the daemon should go without hearing a heartbeat back from
+    /// this client before concluding the peer died (a dropped SSH link,
+    /// say) and internally detaching the session so it stops showing
+    /// `AttachStatus::Busy` to everyone else. In milliseconds; 0 means
+    /// "use the daemon's default".
+    pub heartbeat_deadline_ms: u64,
 }
 
 impl AttachHeader {
@@ -187,6 +239,39 @@ impl AttachHeader {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AttachReplyHeader {
     pub status: AttachStatus,
+    /// How many bytes of scrollback the daemon is about to send as
+    /// `ChunkKind::Data` chunks before live output resumes, honoring
+    /// `AttachHeader::replay_bytes`. Always 0 when `status` is anything
+    /// other than `AttachStatus::Attached`, since a freshly `Created`
+    /// session has no history to replay. The client can use this count to
+    /// feed the replayed bytes through a terminal-reset sequence before
+    /// handing them to the real terminal, so a redraw doesn't scroll the
+    /// user's existing screen.
+    pub scrollback_bytes: usize,
+    /// The output compression algorithm the daemon picked from
+    /// `AttachHeader::compression`. `ChunkKind::Data` chunks on this
+    /// connection will be tagged `ChunkKind::CompressedData` and encoded
+    /// with this algorithm whenever it isn't `CompressionAlgo::None`.
+    pub compression: CompressionAlgo,
+}
+
+/// CompressionAlgo enumerates the output-compression codecs a client can
+/// opt into via `AttachHeader::compression`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompressionAlgo {
+    /// Send `Data` chunks as-is.
+    None,
+    /// Compress `Data` chunks with zstd.
+    Zstd,
+    /// Compress `Data` chunks with lz4, trading compression ratio for
+    /// lower per-chunk latency.
+    Lz4,
+}
+
+impl Default for CompressionAlgo {
+    fn default() -> Self {
+        CompressionAlgo::None
+    }
 }
 
 /// ListReply is contains a list of active sessions to be displayed to the user.
@@ -200,6 +285,11 @@ pub struct ListReply {
 pub struct Session {
     pub name: String,
     pub started_at_unix_ms: i64,
+    /// The last time the daemon heard a heartbeat back from this session's
+    /// attached client, if any is currently attached. `shpool list` can
+    /// show this so users can tell a session apart from one whose client
+    /// silently died and just hasn't hit the heartbeat deadline yet.
+    pub last_heartbeat_unix_ms: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -242,6 +332,25 @@ pub enum AttachStatus {
 pub enum ChunkKind {
     Data = 0,
     Heartbeat = 1,
+    /// A tagged, out-of-band message multiplexed onto the same stream as
+    /// `Data`. Used on the client->server direction so things like resizes
+    /// can be interleaved with stdin bytes in order, instead of needing a
+    /// separate `SessionMessageRequest` round trip. The payload is a
+    /// bincode-encoded `ControlMessage`.
+    Control = 2,
+    /// A flow-control window update. Sent by the receiver of `Data` chunks
+    /// (the client, in the server->client direction) to announce that it
+    /// is willing to accept that many more bytes than it already has.
+    /// The payload is that count as a little endian `u32`, kept out of bincode since it's
+    /// sent often enough on a hot path that we don't want serde overhead.
+    Credit = 3,
+    /// Like `Data`, but the payload has been compressed with whatever
+    /// `CompressionAlgo` was negotiated in `AttachReplyHeader::compression`.
+    /// The usual length prefix still carries the compressed length; a
+    /// second little endian `u32` ahead of the compressed bytes carries the
+    /// decompressed length, so `Chunk::parse_one` can size-check against
+    /// the caller's buffer before inflating into it.
+    CompressedData = 4,
 }
 
 impl ChunkKind {
@@ -249,11 +358,40 @@ impl ChunkKind {
         match v {
             0 => Ok(ChunkKind::Data),
             1 => Ok(ChunkKind::Heartbeat),
+            2 => Ok(ChunkKind::Control),
+            3 => Ok(ChunkKind::Credit),
+            4 => Ok(ChunkKind::CompressedData),
             _ => Err(anyhow!("unknown FrameKind {}", v)),
         }
     }
 }
 
+/// ControlMessage is the payload of a `ChunkKind::Control` frame. It carries
+/// the same kinds of requests that used to require a dedicated
+/// `SessionMessageRequest` round trip, but inline with the input stream so
+/// they stay ordered with respect to the keystrokes around them.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ControlMessage {
+    /// Resize the session's pty, equivalent to
+    /// `SessionMessageRequestPayload::Resize` but delivered in-band.
+    Resize(ResizeRequest),
+    /// Forward a signal the attach process received to the remote shell.
+    Signal(i32),
+    /// Update a variable in the session's environment, e.g. a refreshed
+    /// `SSH_AUTH_SOCK` after an agent-forwarding reconnect.
+    EnvUpdate { key: String, val: String },
+}
+
+impl ControlMessage {
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        bincode::serialize(self).context("encoding control message")
+    }
+
+    pub fn decode(buf: &[u8]) -> anyhow::Result<Self> {
+        bincode::deserialize(buf).context("decoding control message")
+    }
+}
+
 /// Chunk represents of a chunk of data in the output stream
 ///
 /// format:
@@ -263,6 +401,19 @@ impl ChunkKind {
 /// little endian 4 byte word: length prefix
 /// N bytes: data
 /// ```
+///
+/// Both directions of the socket share this framing, and every `ChunkKind`
+/// can in principle show up on either side:
+///
+/// - `Data`: raw stdin bytes client->server, raw pty output server->client
+///   (or transparently inflated from a `CompressedData` frame on the wire).
+/// - `CompressedData`: server->client only, `Data` compressed with
+///   whatever `CompressionAlgo` was negotiated for the connection.
+/// - `Control`: client->server only, a bincode-encoded `ControlMessage`
+///   (resize, signal, env update).
+/// - `Credit`: client->server only, a flow-control window top-up.
+/// - `Heartbeat`: sent server->client to probe liveness, and echoed
+///   straight back client->server as the reply.
 #[derive(Debug)]
 pub struct Chunk<'data> {
     pub kind: ChunkKind,
@@ -270,6 +421,40 @@ pub struct Chunk<'data> {
 }
 
 impl<'data> Chunk<'data> {
+    /// Encodes a chunk header+body into a fresh buffer. Used on the
+    /// nonblocking attach-loop reactor, where frames get queued into a
+    /// pending-bytes buffer rather than written synchronously like
+    /// `write_to` below does.
+    pub fn encode(kind: ChunkKind, buf: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(5 + buf.len());
+        out.push(kind as u8);
+        out.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+        out.extend_from_slice(buf);
+        out
+    }
+
+    /// Encodes `buf` as a `ChunkKind::CompressedData` frame using `algo`,
+    /// or falls back to a plain uncompressed `Data` frame for
+    /// `CompressionAlgo::None`. The companion of `parse_one`'s
+    /// transparent decompression; `algo` must match whatever the reader
+    /// was told to expect in `AttachReplyHeader::compression`.
+    pub fn encode_compressed(buf: &[u8], algo: CompressionAlgo) -> anyhow::Result<Vec<u8>> {
+        let compressed = match algo {
+            CompressionAlgo::None => return Ok(Self::encode(ChunkKind::Data, buf)),
+            CompressionAlgo::Zstd => {
+                zstd::stream::encode_all(buf, 0).context("zstd-compressing chunk")?
+            },
+            CompressionAlgo::Lz4 => lz4_flex::compress(buf),
+        };
+
+        let mut out = Vec::with_capacity(9 + compressed.len());
+        out.push(ChunkKind::CompressedData as u8);
+        out.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+        out.extend_from_slice(&compressed);
+        Ok(out)
+    }
+
     pub fn write_to<W>(&self, w: &mut W, stop: &AtomicBool) -> io::Result<()>
     where
         W: std::io::Write,
@@ -329,45 +514,347 @@ impl<'data> Chunk<'data> {
         Ok(())
     }
 
-    pub fn read_into<R>(r: &mut R, buf: &'data mut [u8]) -> anyhow::Result<Self>
-    where
-        R: std::io::Read,
-    {
-        let kind = r.read_u8()?;
-        let len = r.read_u32::<LittleEndian>()? as usize;
-        if len as usize > buf.len() {
+    /// Parses at most one chunk off the *front* of `raw`, an accumulator of
+    /// however many bytes have been read off a (possibly nonblocking) fd so
+    /// far. Returns `Ok(None)` if `raw` doesn't yet hold a complete
+    /// header+body, in which case the caller should read more bytes onto
+    /// the end of `raw` and try again; `raw` itself is never partially
+    /// consumed, so this is safe to call repeatedly as more bytes trickle
+    /// in. On success, returns the parsed chunk alongside the number of
+    /// bytes of `raw` it consumed, which the caller should drain off the
+    /// front of its accumulator before parsing the next chunk.
+    ///
+    /// This exists instead of a `Read`-based `read_exact` helper because a
+    /// nonblocking fd can return `WouldBlock` partway through a multi-byte
+    /// field (say, 2 of the 4 length-prefix bytes): `read_exact` has
+    /// already pulled those bytes out of the kernel's socket buffer by the
+    /// time that happens, but returns an error and throws them away, which
+    /// permanently desyncs the stream. Parsing out of an accumulator that
+    /// persists across calls sidesteps the problem entirely: nothing is
+    /// consumed from `raw` until a full chunk is present.
+    ///
+    /// `CompressedData` frames are transparently inflated with
+    /// `compression` and handed back with `kind: ChunkKind::Data`, so
+    /// callers never need to think about compression themselves.
+    /// `compression` must be whichever algorithm was negotiated for this
+    /// connection (`CompressionAlgo::None` if none was, in which case a
+    /// `CompressedData` frame is a protocol error).
+    pub fn parse_one(
+        raw: &'data [u8],
+        buf: &'data mut [u8],
+        compression: CompressionAlgo,
+    ) -> anyhow::Result<Option<(Self, usize)>> {
+        const HEADER_LEN: usize = 5; // 1 byte kind tag + 4 byte length prefix
+        if raw.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let kind = ChunkKind::from_u8(raw[0])?;
+        let len = u32::from_le_bytes(raw[1..5].try_into().unwrap()) as usize;
+
+        if let ChunkKind::CompressedData = kind {
+            const COMPRESSED_HEADER_LEN: usize = HEADER_LEN + 4; // + decompressed length
+            if raw.len() < COMPRESSED_HEADER_LEN {
+                return Ok(None);
+            }
+            let decompressed_len = u32::from_le_bytes(raw[5..9].try_into().unwrap()) as usize;
+            if decompressed_len > buf.len() {
+                return Err(anyhow!(
+                    "decompressed chunk of size {} exceeds size limit of {} bytes",
+                    decompressed_len,
+                    buf.len()
+                ));
+            }
+
+            // Bound the compressed length the same way the uncompressed
+            // path below bounds `len`, before waiting on the body: a
+            // corrupted or malicious frame could otherwise claim a
+            // multi-GB compressed size and have the caller keep growing
+            // its accumulator while it waits for that body to arrive.
+            if len > buf.len() {
+                return Err(anyhow!(
+                    "compressed chunk of size {} exceeds size limit of {} bytes",
+                    len,
+                    buf.len()
+                ));
+            }
+
+            if raw.len() < COMPRESSED_HEADER_LEN + len {
+                return Ok(None);
+            }
+            let compressed = &raw[COMPRESSED_HEADER_LEN..COMPRESSED_HEADER_LEN + len];
+            let decompressed = match compression {
+                CompressionAlgo::None => {
+                    return Err(anyhow!(
+                        "received a compressed chunk but no compression was negotiated"
+                    ))
+                },
+                // `bulk::decompress` caps the output buffer at
+                // `decompressed_len` and errors out instead of over-
+                // allocating, the same way the `Lz4` arm below is already
+                // bounded; `stream::decode_all` has no such cap and would
+                // let a zstd bomb inflate to an arbitrary size in memory
+                // before the length check below ever runs.
+                CompressionAlgo::Zstd => zstd::bulk::decompress(compressed, decompressed_len)
+                    .context("zstd-decompressing chunk")?,
+                CompressionAlgo::Lz4 => lz4_flex::decompress(compressed, decompressed_len)
+                    .context("lz4-decompressing chunk")?,
+            };
+            if decompressed.len() != decompressed_len {
+                return Err(anyhow!(
+                    "decompressed chunk length {} did not match the {} bytes advertised in the frame",
+                    decompressed.len(),
+                    decompressed_len
+                ));
+            }
+            buf[..decompressed_len].copy_from_slice(&decompressed);
+
+            return Ok(Some((
+                Chunk { kind: ChunkKind::Data, buf: &buf[..decompressed_len] },
+                COMPRESSED_HEADER_LEN + len,
+            )));
+        }
+
+        if len > buf.len() {
             return Err(anyhow!(
                 "chunk of size {} exceeds size limit of {} bytes",
                 len,
                 buf.len()
             ));
         }
-        r.read_exact(&mut buf[..len])?;
+        if raw.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
 
-        Ok(Chunk {
-            kind: ChunkKind::from_u8(kind)?,
-            buf: &buf[..len],
-        })
+        Ok(Some((
+            Chunk { kind, buf: &raw[HEADER_LEN..HEADER_LEN + len] },
+            HEADER_LEN + len,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod chunk_tests {
+    use super::*;
+
+    #[test]
+    fn encode_parse_one_roundtrip() {
+        let encoded = Chunk::encode(ChunkKind::Data, b"hello world");
+        let mut buf = [0u8; 64];
+        let (chunk, consumed) =
+            Chunk::parse_one(&encoded, &mut buf, CompressionAlgo::None).unwrap().unwrap();
+        assert!(matches!(chunk.kind, ChunkKind::Data));
+        assert_eq!(chunk.buf, b"hello world");
+        assert_eq!(consumed, encoded.len());
+    }
+
+    #[test]
+    fn parse_one_waits_for_a_split_header() {
+        let encoded = Chunk::encode(ChunkKind::Data, b"hello world");
+        let mut buf = [0u8; 64];
+
+        // Only part of the 4 byte length prefix has arrived yet.
+        assert!(Chunk::parse_one(&encoded[..3], &mut buf, CompressionAlgo::None)
+            .unwrap()
+            .is_none());
+        // Header is complete but the body hasn't fully arrived.
+        assert!(Chunk::parse_one(&encoded[..encoded.len() - 1], &mut buf, CompressionAlgo::None)
+            .unwrap()
+            .is_none());
+        // The rest of the body lands; now it parses.
+        assert!(Chunk::parse_one(&encoded, &mut buf, CompressionAlgo::None)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn parse_one_rejects_oversized_chunk() {
+        let encoded = Chunk::encode(ChunkKind::Data, b"too big for the buffer");
+        let mut buf = [0u8; 4];
+        assert!(Chunk::parse_one(&encoded, &mut buf, CompressionAlgo::None).is_err());
+    }
+
+    #[test]
+    fn parse_one_drains_exactly_one_chunk_at_a_time() {
+        let mut raw = Chunk::encode(ChunkKind::Data, b"first");
+        raw.extend(Chunk::encode(ChunkKind::Heartbeat, &[]));
+        let mut buf = [0u8; 64];
+
+        let (first, consumed) =
+            Chunk::parse_one(&raw, &mut buf, CompressionAlgo::None).unwrap().unwrap();
+        assert_eq!(first.buf, b"first");
+        raw.drain(..consumed);
+
+        let (second, consumed) =
+            Chunk::parse_one(&raw, &mut buf, CompressionAlgo::None).unwrap().unwrap();
+        assert!(matches!(second.kind, ChunkKind::Heartbeat));
+        raw.drain(..consumed);
+        assert!(raw.is_empty());
+    }
+
+    #[test]
+    fn encode_compressed_parse_one_roundtrip_zstd() {
+        let payload = vec![b'x'; 4096];
+        let encoded = Chunk::encode_compressed(&payload, CompressionAlgo::Zstd).unwrap();
+        let mut buf = vec![0u8; payload.len()];
+        let (chunk, consumed) =
+            Chunk::parse_one(&encoded, &mut buf, CompressionAlgo::Zstd).unwrap().unwrap();
+        assert!(matches!(chunk.kind, ChunkKind::Data));
+        assert_eq!(chunk.buf, &payload[..]);
+        assert_eq!(consumed, encoded.len());
+    }
+
+    #[test]
+    fn encode_compressed_parse_one_roundtrip_lz4() {
+        let payload = vec![b'y'; 4096];
+        let encoded = Chunk::encode_compressed(&payload, CompressionAlgo::Lz4).unwrap();
+        let mut buf = vec![0u8; payload.len()];
+        let (chunk, _) =
+            Chunk::parse_one(&encoded, &mut buf, CompressionAlgo::Lz4).unwrap().unwrap();
+        assert_eq!(chunk.buf, &payload[..]);
+    }
+
+    #[test]
+    fn parse_one_rejects_oversized_decompressed_length_before_allocating() {
+        let payload = vec![b'z'; 4096];
+        let encoded = Chunk::encode_compressed(&payload, CompressionAlgo::Zstd).unwrap();
+        // Buffer too small to hold the advertised decompressed length; this
+        // must be rejected from the length prefix alone, without ever
+        // touching the decompressor.
+        let mut buf = [0u8; 16];
+        assert!(Chunk::parse_one(&encoded, &mut buf, CompressionAlgo::Zstd).is_err());
+    }
+
+    #[test]
+    fn parse_one_rejects_oversized_compressed_length_before_allocating() {
+        // A frame that claims a small decompressed size but a compressed
+        // size larger than the buffer: the compressed-length check must
+        // reject this from the header alone.
+        let mut raw = vec![ChunkKind::CompressedData as u8];
+        raw.extend_from_slice(&100u32.to_le_bytes()); // compressed length
+        raw.extend_from_slice(&4u32.to_le_bytes()); // decompressed length
+        raw.extend_from_slice(&[0u8; 100]);
+
+        let mut buf = [0u8; 16];
+        assert!(Chunk::parse_one(&raw, &mut buf, CompressionAlgo::Zstd).is_err());
+    }
+
+    #[test]
+    fn parse_one_rejects_compressed_data_without_negotiated_compression() {
+        let payload = vec![b'w'; 64];
+        let encoded = Chunk::encode_compressed(&payload, CompressionAlgo::Zstd).unwrap();
+        let mut buf = vec![0u8; payload.len()];
+        assert!(Chunk::parse_one(&encoded, &mut buf, CompressionAlgo::None).is_err());
+    }
+}
+
+/// Transport is whatever `Client` and the daemon speak the bincode-over-
+/// socket protocol over. Everything above this (`ConnectHeader`, `Chunk`
+/// framing, `pipe_bytes`) is transport-agnostic and works unchanged
+/// regardless of which kind of stream backs it.
+pub trait Transport: Read + Write + AsRawFd + Send {}
+impl<T: Read + Write + AsRawFd + Send> Transport for T {}
+
+/// TlsConfig carries the materials needed to establish an authenticated,
+/// encrypted connection with a remote daemon: the server name to validate
+/// the daemon's certificate against, the CA that issued it, and this
+/// client's own identity for mutual TLS so the daemon can reject
+/// unrecognized peers with `AttachStatus::Forbidden` before ever seeing a
+/// `ConnectHeader`.
+pub struct TlsConfig {
+    pub server_name: String,
+    pub ca_cert: native_tls::Certificate,
+    pub client_identity: native_tls::Identity,
+}
+
+impl TlsConfig {
+    fn connector(&self) -> anyhow::Result<native_tls::TlsConnector> {
+        native_tls::TlsConnector::builder()
+            .identity(self.client_identity.clone())
+            .add_root_certificate(self.ca_cert.clone())
+            .build()
+            .context("building tls connector")
+    }
+}
+
+/// TlsTransport wraps `native_tls::TlsStream<TcpStream>` so it satisfies
+/// `Transport`. Several `native_tls` backends (notably Schannel and
+/// SecureTransport, unlike OpenSSL) deliberately don't implement `AsRawFd`
+/// on `TlsStream` to stay platform-agnostic, so rather than lean on an impl
+/// that may or may not exist upstream, this delegates `Read`/`Write` and
+/// gets the fd explicitly off the underlying `TcpStream` via `get_ref()`.
+struct TlsTransport(native_tls::TlsStream<net::TcpStream>);
+
+impl Read for TlsTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl AsRawFd for TlsTransport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.get_ref().as_raw_fd()
     }
 }
 
 pub struct Client {
-    pub stream: UnixStream,
+    pub stream: Box<dyn Transport>,
 }
 
 impl Client {
+    /// Connects to a daemon listening on a local unix socket.
     pub fn new<P: AsRef<Path>>(sock: P) -> anyhow::Result<Self> {
         let stream = UnixStream::connect(sock).context("connecting to shpool")?;
-        Ok(Client { stream })
+        Ok(Client { stream: Box::new(stream) })
     }
 
-    pub fn write_connect_header(&mut self, header: ConnectHeader) -> anyhow::Result<()> {
-        let serialize_stream = self
-            .stream
-            .try_clone()
-            .context("cloning stream for reply")?;
-        bincode::serialize_into(serialize_stream, &header).context("writing reply")?;
+    /// Connects to a daemon listening on a TCP port, authenticating it (and
+    /// ourselves, for mutual TLS) before the connection is handed back, so
+    /// a user can attach to a remote daemon directly instead of tunneling a
+    /// unix socket over SSH.
+    pub fn new_tls<A: net::ToSocketAddrs>(addr: A, tls: &TlsConfig) -> anyhow::Result<Self> {
+        let mut last_err = None;
+        let mut tcp = None;
+        for sock_addr in addr.to_socket_addrs().context("resolving daemon address")? {
+            match net::TcpStream::connect_timeout(&sock_addr, consts::TCP_CONNECT_TIMEOUT) {
+                Ok(s) => {
+                    tcp = Some(s);
+                    break;
+                },
+                Err(e) => last_err = Some(e),
+            }
+        }
+        let tcp = tcp.ok_or_else(|| {
+            anyhow!(
+                "connecting to shpool over tcp: {}",
+                last_err
+                    .map(|e| e.to_string())
+                    .unwrap_or_else(|| "no addresses to try".to_string())
+            )
+        })?;
+        // Interactive echo is latency sensitive, and unlike on the unix
+        // socket transport, Nagle's algorithm would otherwise
+        // delay the small per-keystroke chunks pipe_bytes writes.
+        tcp.set_nodelay(true).context("setting tcp nodelay")?;
+
+        let connector = tls.connector()?;
+        let stream = connector
+            .connect(&tls.server_name, tcp)
+            .context("establishing tls session with shpool daemon")?;
+        Ok(Client { stream: Box::new(TlsTransport(stream)) })
+    }
 
+    pub fn write_connect_header(&mut self, header: ConnectHeader) -> anyhow::Result<()> {
+        bincode::serialize_into(&mut self.stream, &header).context("writing reply")?;
         Ok(())
     }
 
@@ -379,166 +866,863 @@ impl Client {
         Ok(reply)
     }
 
-    /// pipe_bytes suffles bytes from std{in,out} to the unix
-    /// socket and back again. It is the main loop of
-    /// `shpool attach`.
-    pub fn pipe_bytes(self) -> anyhow::Result<()> {
-        let stop = AtomicBool::new(false);
+    /// pipe_bytes shuffles bytes from std{in,out} to the daemon
+    /// connection (unix socket or TLS-over-TCP) and back again. It is
+    /// the main loop of `shpool attach`.
+    ///
+    /// Rather than busy-polling each fd on its own thread, this runs a
+    /// single mio reactor that registers stdin, stdout and the socket for
+    /// edge-triggered readiness and only blocks inside `Poll::poll`. Each
+    /// fd is drained completely whenever it becomes readable, and a fd is
+    /// only ever registered for writable interest while there is buffered
+    /// data it couldn't accept yet, per the usual mio echo-server
+    /// state-machine.
+    ///
+    /// `on_ready` is handed a `ControlSender` once the reactor is set up
+    /// but before it starts blocking in `Poll::poll`, so callers (e.g. a
+    /// SIGWINCH handler thread) can stash it and push `ControlMessage`s
+    /// into the attach loop for the remainder of the session; each send
+    /// wakes the reactor immediately rather than waiting for it to next
+    /// notice stdin activity.
+    ///
+    /// `initial_window` is the flow-control window this client negotiated
+    /// in its `AttachHeader` (see `ChunkKind::Credit`); it's used to decide
+    /// how much consumed output to let build up before topping the
+    /// daemon's window back up with a credit frame.
+    ///
+    /// `compression` is the algorithm the daemon echoed back in
+    /// `AttachReplyHeader::compression`, used to inflate `CompressedData`
+    /// chunks arriving from the daemon.
+    ///
+    /// `scrollback_bytes` is `AttachReplyHeader::scrollback_bytes`, the
+    /// number of bytes of replayed scrollback the daemon is about to send
+    /// before live output resumes. It's used to feed those bytes through a
+    /// terminal-reset sequence so redrawing them doesn't scroll the user's
+    /// existing screen.
+    pub fn pipe_bytes<F>(
+        self,
+        on_ready: F,
+        initial_window: u32,
+        compression: CompressionAlgo,
+        scrollback_bytes: usize,
+    ) -> anyhow::Result<()>
+    where
+        F: FnOnce(ControlSender),
+    {
+        let mut stream = self.stream;
 
-        let mut read_client_stream = self.stream.try_clone().context("cloning read stream")?;
-        let mut write_client_stream = self.stream.try_clone().context("cloning read stream")?;
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        let stdin_fd = stdin.as_raw_fd();
+        let stdout_fd = stdout.as_raw_fd();
+        let sock_fd = stream.as_raw_fd();
 
-        thread::scope(|s| {
-            // stdin -> sock
-            let stdin_to_sock_h = s.spawn(|| -> anyhow::Result<()> {
-                info!("pipe_bytes: stdin->sock thread spawned");
+        // `Transport` only promises `Read + Write + AsRawFd`, not a
+        // `set_nonblocking` method (unix/TCP/TLS streams all expose that
+        // differently, if at all), so flip the fd nonblocking directly the
+        // same way we already do for stdin/stdout.
+        nix::fcntl::fcntl(
+            stdin_fd,
+            nix::fcntl::FcntlArg::F_SETFL(nix::fcntl::OFlag::O_NONBLOCK),
+        )
+        .context("setting stdin nonblocking")?;
+        nix::fcntl::fcntl(
+            stdout_fd,
+            nix::fcntl::FcntlArg::F_SETFL(nix::fcntl::OFlag::O_NONBLOCK),
+        )
+        .context("setting stdout nonblocking")?;
+        nix::fcntl::fcntl(
+            sock_fd,
+            nix::fcntl::FcntlArg::F_SETFL(nix::fcntl::OFlag::O_NONBLOCK),
+        )
+        .context("setting socket nonblocking")?;
 
-                let mut stdin = std::io::stdin().lock();
-                let mut buf = vec![0; consts::BUF_SIZE];
+        // A self-pipe lets a `ControlSender::stop()` call from another
+        // thread wake the poller immediately instead of waiting on
+        // JOIN_POLL_DURATION. `stop_r` is closed when this function
+        // returns; `stop_w` is handed to the `ControlSender` below and
+        // closed when that's dropped.
+        let (stop_r, stop_w) = nix::unistd::pipe().context("creating self-pipe")?;
+        let stop_r = OwnedFd(stop_r);
+        nix::fcntl::fcntl(
+            stop_r.0,
+            nix::fcntl::FcntlArg::F_SETFL(nix::fcntl::OFlag::O_NONBLOCK),
+        )
+        .context("setting self-pipe nonblocking")?;
 
-                nix::fcntl::fcntl(
-                    stdin.as_raw_fd(),
-                    nix::fcntl::FcntlArg::F_SETFL(nix::fcntl::OFlag::O_NONBLOCK),
-                )
-                .context("setting stdin nonblocking")?;
+        let mut poll = Poll::new().context("creating mio poller")?;
+        poll.registry()
+            .register(&mut SourceFd(&stdin_fd), STDIN, Interest::READABLE)
+            .context("registering stdin with poller")?;
+        poll.registry()
+            .register(&mut SourceFd(&sock_fd), SOCK, Interest::READABLE)
+            .context("registering socket with poller")?;
+        poll.registry()
+            .register(&mut SourceFd(&stop_r.0), STOP, Interest::READABLE)
+            .context("registering self-pipe with poller")?;
 
-                loop {
-                    if stop.load(Ordering::Relaxed) {
-                        info!("pipe_bytes: stdin->sock: recvd stop msg (1)");
-                        return Ok(());
-                    }
+        let (control_tx, control_rx) = mpsc::channel();
+        let waker = Arc::new(Waker::new(poll.registry(), CONTROL).context("creating control waker")?);
+        on_ready(ControlSender { tx: control_tx, waker, stop_w });
 
-                    let nread = match stdin.read(&mut buf) {
-                        Ok(n) => n,
-                        Err(e) => {
-                            if e.kind() == std::io::ErrorKind::WouldBlock {
-                                trace!("pipe_bytes: stdin->sock: read: WouldBlock");
-                                thread::sleep(consts::PIPE_POLL_DURATION);
-                                continue;
-                            }
-                            return Err(e).context("reading stdin from user");
-                        },
-                    };
-
-                    debug!("pipe_bytes: stdin->sock: read {} bytes", nread);
-
-                    let mut to_write = &buf[..nread];
-                    debug!(
-                        "pipe_bytes: stdin->sock: created to_write='{}'",
-                        String::from_utf8_lossy(to_write)
-                    );
-                    while to_write.len() > 0 {
-                        if stop.load(Ordering::Relaxed) {
-                            info!("pipe_bytes: stdin->sock: recvd stop msg (2)");
-                            return Ok(());
-                        }
+        let mut stdin = stdin.lock();
+        let mut stdout = stdout.lock();
 
-                        let nwritten = write_client_stream
-                            .write(to_write)
-                            .context("writing chunk to server")?;
-                        to_write = &to_write[nwritten..];
-                        trace!(
-                            "pipe_bytes: stdin->sock: to_write={}",
-                            String::from_utf8_lossy(to_write)
-                        );
-                    }
+        let mut read_buf = vec![0u8; consts::BUF_SIZE];
+        let mut chunk_buf = vec![0u8; consts::BUF_SIZE];
 
-                    write_client_stream.flush().context("flushing client")?;
-                }
-            });
+        // Bytes we've read but couldn't fully hand off to the other side of
+        // the pipe yet, because that side returned WouldBlock partway
+        // through. `*_writable` tracks whether we've registered for
+        // writable readiness on that fd's behalf so we don't re-register on
+        // every event loop iteration.
+        let mut pending_to_sock: Vec<u8> = Vec::new();
+        let mut sock_writable = false;
+        let mut pending_to_stdout: Vec<u8> = Vec::new();
+        let mut stdout_writable = false;
 
-            // sock -> stdout
-            let sock_to_stdout_h = s.spawn(|| -> anyhow::Result<()> {
-                info!("pipe_bytes: sock->stdout thread spawned");
+        // Raw bytes read off the socket that haven't formed a complete
+        // `Chunk` yet. This is never drained by a `Read`-based helper that could throw
+        // partial reads away on `WouldBlock`; `Chunk::parse_one` only ever
+        // consumes whole chunks off the front of it.
+        let mut sock_read_buf: Vec<u8> = Vec::new();
 
-                let mut stdout = std::io::stdout().lock();
-                let mut buf = vec![0; consts::BUF_SIZE];
+        // Flow control: rather than ack every single chunk, we batch up
+        // `credit_owed` bytes of `Data` actually flushed out to the
+        // terminal and only top the daemon's window back up once it
+        // crosses `credit_step`. Crediting on flush rather than on however
+        // many bytes we merely dequeued off the socket is what makes this
+        // backpressure reach the daemon's pty reader instead of just
+        // relocating the unbounded buffer to `pending_to_stdout`: a client
+        // whose terminal can't keep up stops crediting, which stops the
+        // daemon from sending more, same as if the terminal itself were
+        // slow to read.
+        let window = if initial_window == 0 {
+            consts::DEFAULT_FLOW_CONTROL_WINDOW
+        } else {
+            initial_window
+        };
+        let credit_step = std::cmp::max(window / 2, 1);
+        let mut credit_owed: u32 = 0;
 
-                loop {
-                    if stop.load(Ordering::Relaxed) {
-                        info!("pipe_bytes: sock->stdout: recvd stop msg (1)");
-                        return Ok(());
-                    }
+        // How many bytes at the front of `pending_to_stdout` are not
+        // creditable `Data` payload (namely `consts::SCROLLBACK_RESET_SEQ`,
+        // queued directly rather than arriving as a chunk). Bytes flushed
+        // out of `pending_to_stdout` only count toward `credit_owed` once
+        // this reaches zero.
+        let mut noncredit_pending: usize = 0;
 
-                    let chunk = Chunk::read_into(&mut read_client_stream, &mut buf)
-                        .context("reading output chunk from daemon")?;
+        // Bytes of replayed scrollback we still expect before live output
+        // resumes. The reset sequence is prepended once, right before the
+        // first byte of scrollback hits `pending_to_stdout`, so the
+        // replay redraws in place instead of scrolling the user's screen.
+        let mut scrollback_remaining = scrollback_bytes;
+        let mut sent_scrollback_reset = false;
 
-                    if chunk.buf.len() > 0 {
-                        debug!(
-                            "pipe_bytes: sock->stdout: chunk='{}' kind={:?} len={}",
-                            String::from_utf8_lossy(chunk.buf),
-                            chunk.kind,
-                            chunk.buf.len()
-                        );
-                    }
+        // Set once stdin hits EOF. We can't tear the reactor down the
+        // moment this happens, since there may still be unsent bytes
+        // sitting in `pending_to_sock` (queued from stdin before the
+        // socket could accept them) that need to actually reach the
+        // daemon first; see the drain check at the bottom of the loop.
+        let mut stdin_eof = false;
+
+        let mut events = Events::with_capacity(16);
+        'poll: loop {
+            poll.poll(&mut events, None).context("polling")?;
 
-                    let mut to_write = &chunk.buf[..];
-                    match chunk.kind {
-                        ChunkKind::Heartbeat => {
-                            trace!("pipe_bytes: got heartbeat chunk");
-                        },
-                        ChunkKind::Data => {
-                            while to_write.len() > 0 {
-                                if stop.load(Ordering::Relaxed) {
-                                    info!("pipe_bytes: sock->stdout: recvd stop msg (2)");
-                                    return Ok(());
-                                }
-
-                                debug!("pipe_bytes: sock->stdout: about to select on stdout");
-                                let mut stdout_set = nix::sys::select::FdSet::new();
-                                stdout_set.insert(stdout.as_raw_fd());
-                                let mut poll_dur = consts::PIPE_POLL_DURATION_TIMEVAL.clone();
-                                let nready = nix::sys::select::select(
-                                    None,
-                                    None,
-                                    Some(&mut stdout_set),
-                                    None,
-                                    Some(&mut poll_dur),
-                                )
-                                .context("selecting on stdout")?;
-                                if nready == 0 || !stdout_set.contains(stdout.as_raw_fd()) {
-                                    continue;
-                                }
-
-                                let nwritten =
-                                    stdout.write(to_write).context("writing chunk to stdout")?;
-                                debug!("pipe_bytes: sock->stdout: wrote {} stdout bytes", nwritten);
-                                to_write = &to_write[nwritten..];
+            for event in events.iter() {
+                match event.token() {
+                    STOP => {
+                        info!("pipe_bytes: recvd stop signal, shutting down");
+                        break 'poll;
+                    },
+
+                    STDIN => {
+                        // Piped (non-interactive) stdin delivers its last
+                        // chunk of data and EOF as two separate `read`
+                        // calls, so we can't just bail out on `Ok(0)`
+                        // here: any bytes this same loop already queued
+                        // into `pending_to_sock` from an earlier iteration
+                        // still need to reach the daemon before we tear the
+                        // reactor down, or they're silently lost. Since
+                        // `drain_to_sock` only writes as much as the socket
+                        // will currently accept, reaching EOF doesn't mean
+                        // we can exit immediately either: we just stop
+                        // reading stdin and let the loop keep running,
+                        // servicing `SOCK`'s writable readiness same as
+                        // always, until `pending_to_sock` actually empties
+                        // (see the drain check below).
+                        loop {
+                            match stdin.read(&mut read_buf) {
+                                Ok(0) => {
+                                    stdin_eof = true;
+                                    poll.registry()
+                                        .deregister(&mut SourceFd(&stdin_fd))
+                                        .context("deregistering stdin after eof")?;
+                                    break;
+                                },
+                                Ok(n) => {
+                                    debug!("pipe_bytes: stdin->sock: read {} bytes", n);
+                                    pending_to_sock
+                                        .extend(Chunk::encode(ChunkKind::Data, &read_buf[..n]));
+                                },
+                                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                                Err(e) => return Err(e).context("reading stdin from user"),
                             }
+                        }
+                        drain_to_sock(
+                            stream.as_mut(),
+                            poll.registry(),
+                            &mut pending_to_sock,
+                            &mut sock_writable,
+                        )?;
+                    },
+
+                    CONTROL => {
+                        while let Ok(msg) = control_rx.try_recv() {
+                            debug!("pipe_bytes: control message: {:?}", msg);
+                            let payload = msg.encode().context("encoding control message")?;
+                            pending_to_sock.extend(Chunk::encode(ChunkKind::Control, &payload));
+                        }
+                        drain_to_sock(
+                            stream.as_mut(),
+                            poll.registry(),
+                            &mut pending_to_sock,
+                            &mut sock_writable,
+                        )?;
+                    },
+
+                    SOCK => {
+                        // A single edge-triggered event can be readable and
+                        // writable at once (e.g. we have a pending write
+                        // queued *and* the daemon just sent more output);
+                        // both must run every time they're set; an
+                        // if/else-if here would silently drop whichever
+                        // one loses, and since we're edge-triggered that
+                        // edge never comes back.
+                        if event.is_writable() {
+                            drain_to_sock(
+                                stream.as_mut(),
+                                poll.registry(),
+                                &mut pending_to_sock,
+                                &mut sock_writable,
+                            )?;
+                        }
 
-                            if let Err(e) = stdout.flush() {
-                                if e.kind() == std::io::ErrorKind::WouldBlock {
-                                    // If the fd is busy, we are likely just getting
-                                    // flooded with output and don't need to worry about
-                                    // flushing every last byte. Flushing is really
-                                    // about interactive situations where we want to
-                                    // see echoed bytes immediately.
-                                    continue;
-                                }
+                        if !event.is_readable() {
+                            continue;
+                        }
+
+                        // Pull whatever bytes the kernel currently has
+                        // buffered for us onto the end of `sock_read_buf`.
+                        // Parsing happens separately, below, once all of
+                        // this readable edge's bytes are accounted for:
+                        // that way a `Chunk` never straddles a `WouldBlock`
+                        // in a way that could lose bytes, since nothing is
+                        // consumed from `sock_read_buf` until a full chunk
+                        // is present in it.
+                        loop {
+                            match stream.read(&mut read_buf) {
+                                Ok(0) => return Err(anyhow!("daemon hung up")),
+                                Ok(n) => sock_read_buf.extend_from_slice(&read_buf[..n]),
+                                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                                Err(e) => return Err(e).context("reading from daemon socket"),
                             }
-                            debug!("pipe_bytes: sock->stdout: flushed stdout");
-                        },
-                    }
+                        }
+
+                        loop {
+                            let (chunk, consumed) =
+                                match Chunk::parse_one(&sock_read_buf, &mut chunk_buf, compression)
+                                {
+                                    Ok(Some(parsed)) => parsed,
+                                    Ok(None) => break,
+                                    Err(e) => return Err(e).context("reading output chunk from daemon"),
+                                };
+
+                            match chunk.kind {
+                                ChunkKind::Heartbeat => {
+                                    trace!("pipe_bytes: got heartbeat chunk, replying");
+                                    // Bounce the heartbeat straight back so the
+                                    // daemon can tell this client is still
+                                    // alive and reset its liveness deadline.
+                                    pending_to_sock
+                                        .extend(Chunk::encode(ChunkKind::Heartbeat, &[]));
+                                },
+                                ChunkKind::Data => {
+                                    debug!(
+                                        "pipe_bytes: sock->stdout: chunk len={}",
+                                        chunk.buf.len()
+                                    );
+
+                                    queue_data_chunk_for_stdout(
+                                        chunk.buf,
+                                        &mut pending_to_stdout,
+                                        &mut scrollback_remaining,
+                                        &mut sent_scrollback_reset,
+                                        &mut noncredit_pending,
+                                    );
+                                },
+                                ChunkKind::Control => {
+                                    // The server->client direction never sends
+                                    // control frames today, but we don't want
+                                    // a surprise one to tear down the attach
+                                    // loop.
+                                    debug!("pipe_bytes: unexpected control chunk from daemon");
+                                },
+                                ChunkKind::Credit => {
+                                    // Credit flows client->server; seeing one
+                                    // here would mean the daemon is confused
+                                    // about which end of the connection it is.
+                                    debug!("pipe_bytes: unexpected credit chunk from daemon");
+                                },
+                                ChunkKind::CompressedData => {
+                                    // `Chunk::parse_one` always inflates
+                                    // `CompressedData` frames and hands them
+                                    // back tagged `ChunkKind::Data`, so this
+                                    // arm is unreachable in practice; it's
+                                    // here so the match stays exhaustive if
+                                    // that invariant ever changes.
+                                    unreachable!(
+                                        "Chunk::parse_one never returns a raw CompressedData chunk"
+                                    )
+                                },
+                            }
+
+                            sock_read_buf.drain(..consumed);
+                        }
+                        flush_stdout_and_credit(
+                            &mut stdout,
+                            poll.registry(),
+                            stdout_fd,
+                            &mut pending_to_stdout,
+                            &mut stdout_writable,
+                            &mut noncredit_pending,
+                            &mut credit_owed,
+                            credit_step,
+                            &mut pending_to_sock,
+                        )?;
+
+                        // Flushes any heartbeat reply and/or credit top-up
+                        // queued above, in addition to whatever stdin bytes
+                        // were already pending.
+                        drain_to_sock(
+                            stream.as_mut(),
+                            poll.registry(),
+                            &mut pending_to_sock,
+                            &mut sock_writable,
+                        )?;
+                    },
+
+                    STDOUT => {
+                        flush_stdout_and_credit(
+                            &mut stdout,
+                            poll.registry(),
+                            stdout_fd,
+                            &mut pending_to_stdout,
+                            &mut stdout_writable,
+                            &mut noncredit_pending,
+                            &mut credit_owed,
+                            credit_step,
+                            &mut pending_to_sock,
+                        )?;
+                        // A credit top-up queued above (the terminal
+                        // finally caught up enough to cross `credit_step`)
+                        // needs flushing same as any other write to the
+                        // daemon.
+                        drain_to_sock(
+                            stream.as_mut(),
+                            poll.registry(),
+                            &mut pending_to_sock,
+                            &mut sock_writable,
+                        )?;
+                    },
+
+                    _ => unreachable!("pipe_bytes: unregistered mio token"),
                 }
-            });
+            }
+
+            // Now that we've serviced whatever was ready this wakeup
+            // (including, possibly, the socket becoming writable and
+            // draining more of `pending_to_sock`), it's safe to tear the
+            // reactor down if stdin is gone and nothing is left queued for
+            // the daemon.
+            if stdin_eof && pending_to_sock.is_empty() {
+                break 'poll;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A bare fd that gets `close`d when dropped, for the self-pipe read end
+/// `pipe_bytes` owns for its own lifetime (the write end is owned by the
+/// `ControlSender` handed out to callers, and closed when that's dropped).
+struct OwnedFd(RawFd);
+
+impl Drop for OwnedFd {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.0);
+    }
+}
 
+const STDIN: Token = Token(0);
+const STDOUT: Token = Token(1);
+const SOCK: Token = Token(2);
+const STOP: Token = Token(3);
+const CONTROL: Token = Token(4);
+
+/// ControlSender lets some other part of the process (e.g. a SIGWINCH
+/// handler thread) enqueue a `ControlMessage` onto a running `pipe_bytes`
+/// attach loop, or ask it to shut down, waking the reactor immediately
+/// rather than waiting for it to next happen to poll.
+pub struct ControlSender {
+    tx: mpsc::Sender<ControlMessage>,
+    waker: Arc<Waker>,
+    stop_w: RawFd,
+}
+
+impl ControlSender {
+    pub fn send(&self, msg: ControlMessage) -> anyhow::Result<()> {
+        self.tx
+            .send(msg)
+            .map_err(|_| anyhow!("attach loop control channel closed"))?;
+        self.waker.wake().context("waking attach loop")?;
+        Ok(())
+    }
+
+    /// Unwinds the attach loop this handle belongs to, as soon as it next
+    /// wakes up.
+    pub fn stop(&self) -> anyhow::Result<()> {
+        nix::unistd::write(self.stop_w, &[0u8]).context("waking attach loop to stop")?;
+        Ok(())
+    }
+}
+
+impl Drop for ControlSender {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.stop_w);
+    }
+}
+
+/// Writes as much of `pending` to the socket as it will currently accept,
+/// registering (or unregistering) the socket for writable readiness
+/// depending on whether bytes are left over.
+fn drain_to_sock(
+    stream: &mut dyn Transport,
+    registry: &Registry,
+    pending: &mut Vec<u8>,
+    writable_registered: &mut bool,
+) -> anyhow::Result<()> {
+    while !pending.is_empty() {
+        match stream.write(pending) {
+            Ok(n) => {
+                pending.drain(..n);
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e).context("writing chunk to server"),
+        }
+    }
+
+    let sock_fd = stream.as_raw_fd();
+    if pending.is_empty() {
+        if *writable_registered {
+            registry
+                .reregister(&mut SourceFd(&sock_fd), SOCK, Interest::READABLE)
+                .context("reregistering socket for readable only")?;
+            *writable_registered = false;
+        }
+    } else if !*writable_registered {
+        registry
+            .reregister(
+                &mut SourceFd(&sock_fd),
+                SOCK,
+                Interest::READABLE | Interest::WRITABLE,
+            )
+            .context("reregistering socket for writable")?;
+        *writable_registered = true;
+    }
+
+    Ok(())
+}
+
+/// Writes as much of `pending` to stdout as it will currently accept,
+/// registering (or unregistering) stdout for writable readiness depending
+/// on whether bytes are left over.
+fn drain_to_stdout(
+    stdout: &mut io::StdoutLock,
+    registry: &Registry,
+    stdout_fd: RawFd,
+    pending: &mut Vec<u8>,
+    writable_registered: &mut bool,
+) -> anyhow::Result<()> {
+    while !pending.is_empty() {
+        match stdout.write(pending) {
+            Ok(n) => {
+                pending.drain(..n);
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e).context("writing chunk to stdout"),
+        }
+    }
+    if pending.is_empty() {
+        if let Err(e) = stdout.flush() {
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e).context("flushing stdout");
+            }
+        }
+        if *writable_registered {
+            registry
+                .deregister(&mut SourceFd(&stdout_fd))
+                .context("deregistering stdout")?;
+            *writable_registered = false;
+        }
+    } else if !*writable_registered {
+        registry
+            .register(&mut SourceFd(&stdout_fd), STDOUT, Interest::WRITABLE)
+            .context("registering stdout for writable")?;
+        *writable_registered = true;
+    }
+
+    Ok(())
+}
+
+/// Queues a `Data` chunk just read off the socket onto `pending_to_stdout`,
+/// prepending `consts::SCROLLBACK_RESET_SEQ` first if this is the first
+/// chunk of a scrollback replay (so the replay redraws in place instead of
+/// scrolling the user's screen) and tracking how much of the replay is left
+/// via `scrollback_remaining`/`sent_scrollback_reset`. The reset sequence's
+/// length is added to `noncredit_pending` since it never passed through
+/// flow control and so shouldn't be credited back to the daemon when it's
+/// flushed; see `credit_for_flush`.
+fn queue_data_chunk_for_stdout(
+    chunk_buf: &[u8],
+    pending_to_stdout: &mut Vec<u8>,
+    scrollback_remaining: &mut usize,
+    sent_scrollback_reset: &mut bool,
+    noncredit_pending: &mut usize,
+) {
+    if *scrollback_remaining > 0 && !*sent_scrollback_reset {
+        trace!("pipe_bytes: replaying scrollback, resetting terminal");
+        pending_to_stdout.extend_from_slice(consts::SCROLLBACK_RESET_SEQ);
+        *noncredit_pending += consts::SCROLLBACK_RESET_SEQ.len();
+        *sent_scrollback_reset = true;
+    }
+    pending_to_stdout.extend_from_slice(chunk_buf);
+    if *scrollback_remaining > 0 {
+        *scrollback_remaining = scrollback_remaining.saturating_sub(chunk_buf.len());
+        if *scrollback_remaining == 0 {
+            trace!("pipe_bytes: scrollback replay complete, resuming live output");
+        }
+    }
+}
+
+/// Given that `flushed` bytes were just written out of the front of
+/// `pending_to_stdout`, returns how many of them are creditable `Data`
+/// payload as opposed to something queued directly without ever passing
+/// through flow control (namely `consts::SCROLLBACK_RESET_SEQ`).
+/// `noncredit_remaining` tracks how many bytes at the front of the queue
+/// are still of the latter kind; it's decremented by however much of that
+/// prefix `flushed` covers.
+fn credit_for_flush(flushed: usize, noncredit_remaining: &mut usize) -> u32 {
+    let skipped = std::cmp::min(flushed, *noncredit_remaining);
+    *noncredit_remaining -= skipped;
+    (flushed - skipped) as u32
+}
+
+/// Flushes `pending_to_stdout` via `drain_to_stdout` and credits the
+/// daemon's flow-control window for whatever `Data` payload actually made
+/// it out to the terminal in the process, topping the window back up with
+/// a `ChunkKind::Credit` frame (queued into `pending_to_sock`, not yet
+/// flushed to the socket) once `credit_owed` crosses `credit_step`.
+///
+/// Crediting here, after the write succeeds, rather than as soon as bytes
+/// are dequeued off the socket, is what keeps the backpressure
+/// `ChunkKind::Credit` exists for pointed at the actual bottleneck: a
+/// client whose terminal can't keep up stops crediting, which stops the
+/// daemon from sending more, instead of `pending_to_stdout` growing
+/// without bound while the daemon is none the wiser.
+#[allow(clippy::too_many_arguments)]
+fn flush_stdout_and_credit(
+    stdout: &mut io::StdoutLock,
+    registry: &Registry,
+    stdout_fd: RawFd,
+    pending_to_stdout: &mut Vec<u8>,
+    stdout_writable: &mut bool,
+    noncredit_remaining: &mut usize,
+    credit_owed: &mut u32,
+    credit_step: u32,
+    pending_to_sock: &mut Vec<u8>,
+) -> anyhow::Result<()> {
+    let before = pending_to_stdout.len();
+    drain_to_stdout(stdout, registry, stdout_fd, pending_to_stdout, stdout_writable)?;
+    let flushed = before - pending_to_stdout.len();
+
+    *credit_owed = credit_owed.saturating_add(credit_for_flush(flushed, noncredit_remaining));
+    if *credit_owed >= credit_step {
+        trace!("pipe_bytes: topping up daemon window by {}", credit_owed);
+        pending_to_sock.extend(Chunk::encode(ChunkKind::Credit, &credit_owed.to_le_bytes()));
+        *credit_owed = 0;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod scrollback_tests {
+    use super::*;
+
+    #[test]
+    fn first_chunk_of_replay_gets_reset_prefix() {
+        let mut pending_to_stdout = Vec::new();
+        let mut scrollback_remaining = 5;
+        let mut sent_scrollback_reset = false;
+        let mut noncredit_pending = 0;
+
+        queue_data_chunk_for_stdout(
+            b"hello",
+            &mut pending_to_stdout,
+            &mut scrollback_remaining,
+            &mut sent_scrollback_reset,
+            &mut noncredit_pending,
+        );
+
+        let mut expected = consts::SCROLLBACK_RESET_SEQ.to_vec();
+        expected.extend_from_slice(b"hello");
+        assert_eq!(pending_to_stdout, expected);
+        assert!(sent_scrollback_reset);
+        assert_eq!(noncredit_pending, consts::SCROLLBACK_RESET_SEQ.len());
+        assert_eq!(scrollback_remaining, 0);
+    }
+
+    #[test]
+    fn later_chunks_of_replay_skip_the_reset_prefix() {
+        let mut pending_to_stdout = Vec::new();
+        let mut scrollback_remaining = 10;
+        let mut sent_scrollback_reset = true;
+        let mut noncredit_pending = 0;
+
+        queue_data_chunk_for_stdout(
+            b"world",
+            &mut pending_to_stdout,
+            &mut scrollback_remaining,
+            &mut sent_scrollback_reset,
+            &mut noncredit_pending,
+        );
+
+        assert_eq!(pending_to_stdout, b"world");
+        assert_eq!(noncredit_pending, 0);
+        assert_eq!(scrollback_remaining, 5);
+    }
+
+    #[test]
+    fn live_output_outside_a_replay_is_untouched() {
+        let mut pending_to_stdout = Vec::new();
+        let mut scrollback_remaining = 0;
+        let mut sent_scrollback_reset = false;
+        let mut noncredit_pending = 0;
+
+        queue_data_chunk_for_stdout(
+            b"live",
+            &mut pending_to_stdout,
+            &mut scrollback_remaining,
+            &mut sent_scrollback_reset,
+            &mut noncredit_pending,
+        );
+
+        assert_eq!(pending_to_stdout, b"live");
+        assert!(!sent_scrollback_reset);
+        assert_eq!(noncredit_pending, 0);
+        assert_eq!(scrollback_remaining, 0);
+    }
+}
+
+#[cfg(test)]
+mod control_tests {
+    use super::*;
+
+    #[test]
+    fn resize_roundtrip() {
+        let msg = ControlMessage::Resize(ResizeRequest {
+            tty_size: tty::Size { rows: 24, cols: 80 },
+        });
+        let decoded = ControlMessage::decode(&msg.encode().unwrap()).unwrap();
+        match decoded {
+            ControlMessage::Resize(req) => {
+                assert_eq!(req.tty_size.rows, 24);
+                assert_eq!(req.tty_size.cols, 80);
+            },
+            other => panic!("expected Resize, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn signal_roundtrip() {
+        let msg = ControlMessage::Signal(15);
+        let decoded = ControlMessage::decode(&msg.encode().unwrap()).unwrap();
+        match decoded {
+            ControlMessage::Signal(sig) => assert_eq!(sig, 15),
+            other => panic!("expected Signal, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn env_update_roundtrip() {
+        let msg = ControlMessage::EnvUpdate {
+            key: "SSH_AUTH_SOCK".to_string(),
+            val: "/tmp/ssh-agent.sock".to_string(),
+        };
+        let decoded = ControlMessage::decode(&msg.encode().unwrap()).unwrap();
+        match decoded {
+            ControlMessage::EnvUpdate { key, val } => {
+                assert_eq!(key, "SSH_AUTH_SOCK");
+                assert_eq!(val, "/tmp/ssh-agent.sock");
+            },
+            other => panic!("expected EnvUpdate, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod credit_tests {
+    use super::*;
+
+    #[test]
+    fn credit_for_flush_all_creditable() {
+        let mut noncredit_remaining = 0;
+        assert_eq!(credit_for_flush(128, &mut noncredit_remaining), 128);
+        assert_eq!(noncredit_remaining, 0);
+    }
+
+    #[test]
+    fn credit_for_flush_skips_noncredit_prefix() {
+        let mut noncredit_remaining = 10;
+        // Only the reset-sequence prefix was flushed; none of it is
+        // creditable yet.
+        assert_eq!(credit_for_flush(6, &mut noncredit_remaining), 0);
+        assert_eq!(noncredit_remaining, 4);
+
+        // The rest of the reset sequence plus some real data flushes next.
+        assert_eq!(credit_for_flush(20, &mut noncredit_remaining), 16);
+        assert_eq!(noncredit_remaining, 0);
+    }
+
+    #[test]
+    fn credit_for_flush_nothing_flushed() {
+        let mut noncredit_remaining = 5;
+        assert_eq!(credit_for_flush(0, &mut noncredit_remaining), 0);
+        assert_eq!(noncredit_remaining, 5);
+    }
+}
+
+#[cfg(test)]
+mod reactor_tests {
+    use super::*;
+    use std::{
+        os::unix::{io::FromRawFd, net::UnixStream},
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    struct StdioGuard {
+        saved_stdin: RawFd,
+        saved_stdout: RawFd,
+    }
+
+    impl StdioGuard {
+        fn redirect(new_stdin: RawFd, new_stdout: RawFd) -> Self {
+            let saved_stdin = nix::unistd::dup(0).unwrap();
+            let saved_stdout = nix::unistd::dup(1).unwrap();
+            nix::unistd::dup2(new_stdin, 0).unwrap();
+            nix::unistd::dup2(new_stdout, 1).unwrap();
+            StdioGuard { saved_stdin, saved_stdout }
+        }
+    }
+
+    impl Drop for StdioGuard {
+        fn drop(&mut self) {
+            let _ = nix::unistd::dup2(self.saved_stdin, 0);
+            let _ = nix::unistd::dup2(self.saved_stdout, 1);
+            let _ = nix::unistd::close(self.saved_stdin);
+            let _ = nix::unistd::close(self.saved_stdout);
+        }
+    }
+
+    // Drives the real `pipe_bytes` reactor over a `UnixStream::pair()`,
+    // with process stdin/stdout redirected onto pipes, and forces the
+    // socket side into `WouldBlock` by queuing more stdin data than the
+    // unix socket's send buffer can absorb in one go right as stdin EOFs.
+    // This is the scenario the EOF drain check at the bottom of the
+    // `pipe_bytes` loop exists for: exiting after a single flush would
+    // silently truncate it.
+    #[test]
+    fn pipe_bytes_flushes_stdin_before_exiting_on_eof() {
+        let (stdin_r, stdin_w) = nix::unistd::pipe().unwrap();
+        let (stdout_r, stdout_w) = nix::unistd::pipe().unwrap();
+        let _stdio_guard = StdioGuard::redirect(stdin_r, stdout_w);
+        let _ = nix::unistd::close(stdin_r);
+        let _ = nix::unistd::close(stdout_w);
+
+        thread::spawn(move || {
+            let mut f = unsafe { std::fs::File::from_raw_fd(stdout_r) };
+            let mut buf = [0u8; 4096];
             loop {
-                if stdin_to_sock_h.is_finished() || sock_to_stdout_h.is_finished() {
-                    stop.store(true, Ordering::Relaxed);
-                    break;
+                match std::io::Read::read(&mut f, &mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {},
                 }
-                thread::sleep(consts::JOIN_POLL_DURATION);
-            }
-            match stdin_to_sock_h.join() {
-                Ok(v) => v?,
-                Err(panic_err) => std::panic::resume_unwind(panic_err),
             }
-            match sock_to_stdout_h.join() {
-                Ok(v) => v?,
-                Err(panic_err) => std::panic::resume_unwind(panic_err),
+        });
+
+        let (client_sock, daemon_sock) = UnixStream::pair().unwrap();
+        let client = Client { stream: Box::new(client_sock) };
+
+        let payload = vec![b'A'; 4 * 1024 * 1024];
+        let payload_for_writer = payload.clone();
+        let writer = thread::spawn(move || {
+            let mut f = unsafe { std::fs::File::from_raw_fd(stdin_w) };
+            std::io::Write::write_all(&mut f, &payload_for_writer).unwrap();
+        });
+
+        // `on_ready` only gets a borrow-free hand-off of the
+        // `ControlSender`; dropping it immediately would close its
+        // self-pipe write end and make the reactor mistake that for a stop
+        // request. Stash it somewhere that outlives the reactor's run, same
+        // as a real caller would keep it around to send resizes/signals
+        // later.
+        let held_sender = Arc::new(Mutex::new(None));
+        let held_sender_for_thread = Arc::clone(&held_sender);
+        let pipe_bytes_thread = thread::spawn(move || {
+            client.pipe_bytes(
+                |sender| *held_sender_for_thread.lock().unwrap() = Some(sender),
+                0,
+                CompressionAlgo::None,
+                0,
+            )
+        });
+
+        thread::sleep(Duration::from_millis(100));
+
+        let mut received = Vec::new();
+        daemon_sock.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut daemon_sock = daemon_sock;
+        while received.len() < payload.len() {
+            let mut header = [0u8; 5];
+            daemon_sock
+                .read_exact(&mut header)
+                .expect("daemon socket closed before all stdin bytes arrived");
+            let len = u32::from_le_bytes(header[1..5].try_into().unwrap()) as usize;
+            let mut body = vec![0u8; len];
+            daemon_sock.read_exact(&mut body).unwrap();
+            if header[0] == ChunkKind::Data as u8 {
+                received.extend_from_slice(&body);
             }
+        }
+
+        writer.join().unwrap();
+        pipe_bytes_thread.join().unwrap().unwrap();
 
-            Ok(())
-        })
+        assert_eq!(received, payload);
     }
 }